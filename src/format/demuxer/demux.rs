@@ -1,12 +1,245 @@
 #![allow(dead_code)]
 
-use std::io::Error;
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom};
+use std::fmt;
+use std::error::Error as StdError;
 use data::packet::Packet;
 
+/// A source a `ByteReader` can pull bytes from.
+///
+/// Most demuxers only ever need forward reads, but some containers need to
+/// jump back to re-read an index or a header once the body has been parsed,
+/// so seeking is supported where the underlying source allows it.
+enum Source<'a> {
+    Read(&'a mut Read),
+    ReadSeek(&'a mut ReadSeek),
+}
+
+/// Marker trait tying `Read` and `Seek` together so a single trait object
+/// reference can be stored for seekable sources.
+pub trait ReadSeek: Read + Seek {}
+impl<T: Read + Seek> ReadSeek for T {}
+
+/// Error returned when a `ByteReader` runs out of data before it could
+/// satisfy a request.
+///
+/// `available` records how many bytes were actually buffered when the
+/// underlying source ran dry, which lets a caller tell a clean end of
+/// stream (`available == 0`, nothing buffered yet) apart from a stream that
+/// died partway through a packet (`0 < available < requested`).
+#[derive(Debug)]
+pub struct UnexpectedEofError {
+    pub requested: usize,
+    pub available: usize,
+}
+
+impl fmt::Display for UnexpectedEofError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "unexpected eof: needed {} bytes, only {} available",
+               self.requested,
+               self.available)
+    }
+}
+
+impl StdError for UnexpectedEofError {
+    fn description(&self) -> &str {
+        "unexpected eof"
+    }
+}
+
+/// A small buffered reader sitting in front of an arbitrary `Read` (plus
+/// `Seek` where available), used to feed bytes into a `Demuxer`.
+///
+/// It keeps an internal fill buffer and a cursor into it, so `peek` can look
+/// ahead without consuming, and a failed `read_exact_or_eof` leaves the
+/// cursor exactly where it was: nothing is consumed unless the full request
+/// could be satisfied, so a caller that gets more data later (a file still
+/// being written, a socket that has more to send) can simply retry.
+pub struct ByteReader<'a> {
+    source: Source<'a>,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl<'a> ByteReader<'a> {
+    pub fn new(input: &'a mut Read) -> ByteReader<'a> {
+        ByteReader {
+            source: Source::Read(input),
+            buf: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    pub fn new_seekable(input: &'a mut ReadSeek) -> ByteReader<'a> {
+        ByteReader {
+            source: Source::ReadSeek(input),
+            buf: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    pub fn is_seekable(&self) -> bool {
+        match self.source {
+            Source::ReadSeek(_) => true,
+            Source::Read(_) => false,
+        }
+    }
+
+    /// Seek within the underlying stream, if it supports it.
+    ///
+    /// This bypasses the fill buffer entirely, so it also drops whatever is
+    /// currently buffered.
+    pub fn seek(&mut self, pos: SeekFrom) -> Result<u64, Error> {
+        let new_pos = match self.source {
+            Source::ReadSeek(ref mut s) => try!(s.seek(pos)),
+            Source::Read(_) => {
+                return Err(Error::new(ErrorKind::Other, "underlying source is not seekable"))
+            }
+        };
+
+        self.buf.clear();
+        self.pos = 0;
+
+        Ok(new_pos)
+    }
+
+    /// Buffer up to `n` bytes without failing on a short stream: returns
+    /// however many bytes could actually be gathered before the source hit
+    /// EOF.
+    fn fill_upto(&mut self, n: usize) -> Result<usize, Error> {
+        if self.pos > 0 {
+            self.buf.drain(0..self.pos);
+            self.pos = 0;
+        }
+
+        while self.buf.len() < n {
+            let mut chunk = [0u8; 4096];
+            let want = ::std::cmp::min(n - self.buf.len(), chunk.len());
+
+            let read = match self.source {
+                Source::Read(ref mut r) => try!(r.read(&mut chunk[..want])),
+                Source::ReadSeek(ref mut r) => try!(r.read(&mut chunk[..want])),
+            };
+
+            if read == 0 {
+                break;
+            }
+
+            self.buf.extend_from_slice(&chunk[..read]);
+        }
+
+        Ok(self.buf.len())
+    }
+
+    fn fill(&mut self, n: usize) -> Result<(), Error> {
+        let available = try!(self.fill_upto(n));
+
+        if available < n {
+            return Err(Error::new(ErrorKind::UnexpectedEof,
+                                   UnexpectedEofError {
+                                       requested: n,
+                                       available: available,
+                                   }));
+        }
+
+        Ok(())
+    }
+
+    /// Look at the next `n` bytes without consuming them.
+    pub fn peek(&mut self, n: usize) -> Result<&[u8], Error> {
+        try!(self.fill(n));
+
+        Ok(&self.buf[self.pos..self.pos + n])
+    }
+
+    /// Look at up to `n` bytes without consuming them and without failing if
+    /// the source has less than `n` left: returns whatever could be
+    /// buffered, which is shorter than `n` only once the source is
+    /// exhausted.
+    pub fn peek_available(&mut self, n: usize) -> Result<&[u8], Error> {
+        let available = try!(self.fill_upto(n));
+
+        Ok(&self.buf[self.pos..self.pos + available])
+    }
+
+    /// Read exactly `n` bytes, advancing the cursor past them.
+    ///
+    /// On `UnexpectedEof` the cursor is left untouched, so the read can be
+    /// retried once more data has arrived.
+    pub fn read_exact_or_eof(&mut self, n: usize) -> Result<&[u8], Error> {
+        try!(self.fill(n));
+
+        let start = self.pos;
+        self.pos += n;
+
+        Ok(&self.buf[start..start + n])
+    }
+}
+
+/// Error a `Demuxer` can report while parsing headers or packets.
+///
+/// Keeping this separate from `std::io::Error` lets a format implementation
+/// say "these bytes are not valid for this format" without fabricating an
+/// I/O error for it, and lets a driver loop tell a recoverable end of
+/// stream apart from genuine corruption.
+#[derive(Debug)]
+pub enum DemuxerError {
+    /// The underlying reader failed.
+    Io(Error),
+    /// The bytes read so far are not valid for this format.
+    InvalidData { reason: &'static str },
+    /// The next packet is not fully available yet; a non-blocking source
+    /// may have more once it is polled again.
+    NeedMoreData,
+    /// The stream ended cleanly at a packet boundary.
+    Eof,
+}
+
+impl fmt::Display for DemuxerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DemuxerError::Io(ref e) => write!(f, "i/o error: {}", e),
+            DemuxerError::InvalidData { reason } => write!(f, "invalid data: {}", reason),
+            DemuxerError::NeedMoreData => write!(f, "need more data"),
+            DemuxerError::Eof => write!(f, "end of stream"),
+        }
+    }
+}
+
+impl StdError for DemuxerError {
+    fn description(&self) -> &str {
+        match *self {
+            DemuxerError::Io(ref e) => e.description(),
+            DemuxerError::InvalidData { reason } => reason,
+            DemuxerError::NeedMoreData => "need more data",
+            DemuxerError::Eof => "end of stream",
+        }
+    }
+}
+
+impl From<Error> for DemuxerError {
+    fn from(err: Error) -> DemuxerError {
+        if err.kind() == ErrorKind::UnexpectedEof {
+            let available = err.get_ref()
+                .and_then(|e| e.downcast_ref::<UnexpectedEofError>())
+                .map_or(0, |e| e.available);
+
+            return if available == 0 {
+                DemuxerError::Eof
+            } else {
+                DemuxerError::NeedMoreData
+            };
+        }
+
+        DemuxerError::Io(err)
+    }
+}
+
 pub trait Demuxer {
     fn open(&mut self);
-    fn read_headers(&mut self) -> Result<(), Error>;
-    fn read_packet(&mut self) -> Result<Packet, Error>;
+    fn read_headers(&mut self, input: &mut ByteReader) -> Result<(), DemuxerError>;
+    fn read_packet(&mut self, input: &mut ByteReader) -> Result<Packet, DemuxerError>;
 }
 
 pub struct DemuxerDescription {
@@ -16,44 +249,116 @@ pub struct DemuxerDescription {
     mime: &'static [&'static str],
 }
 
-/// Least amount of data needed to check the bytestream structure
-/// to match some known format.
+/// Initial amount of data fed to a probe round; formats that need to look
+/// further into the stream can ask for more via `ProbeResult::NeedMoreData`.
 pub const PROBE_DATA: usize = 4 * 1024;
 
-/// Probe threshold values
-pub enum Score {
-    /// Minimum acceptable value, a file matched just by the extension
-    EXTENSION = 50,
-    /// The underlying layer provides the information, trust it up to a point
-    MIME = 75,
-    /// The data actually match a format structure
-    MAX = 100,
+/// Suggested value for `probe`'s `max_probe_data` argument: an upper bound
+/// on how much data it will buffer while looking for a match, no matter how
+/// much more a format asks for. Bounds memory use against a format that
+/// keeps asking for more data from a never-ending source.
+pub const MAX_PROBE_DATA: usize = 1024 * 1024;
+
+/// Confidence that a chunk of data belongs to a particular format, used as
+/// a threshold: higher is more confident.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Score(u8);
+
+impl Score {
+    /// Nothing in the data suggests this format at all.
+    pub const NONE: Score = Score(0);
+    /// Minimum acceptable value, a file matched just by the extension.
+    pub const EXTENSION: Score = Score(50);
+    /// The underlying layer provides the information, trust it up to a point.
+    pub const MIME: Score = Score(75);
+    /// The data actually match a format structure.
+    pub const MAX: Score = Score(100);
+}
+
+/// Result of handing a format builder a chunk of data to probe.
+pub enum ProbeResult {
+    /// The format reached a verdict on the data it was given.
+    Score(Score),
+    /// The format cannot decide yet; it needs at least this many more bytes
+    /// than it was given.
+    NeedMoreData { at_least: usize },
 }
 
 pub trait DemuxerBuilder {
     fn describe(&self) -> &'static DemuxerDescription;
-    fn probe(&self, data: &[u8; PROBE_DATA]) -> u8;
+    fn probe(&self, data: &[u8]) -> ProbeResult;
     fn alloc(&self) -> Box<Demuxer>;
 }
 
+/// Find the builder best suited to demux `input`.
+///
+/// Starts with `PROBE_DATA` bytes (or `max_probe_data`, if smaller) and
+/// grows the window by the largest `NeedMoreData` request of each round,
+/// until some builder scores `Score::MAX`, no builder asks for more data,
+/// the source runs out, or `max_probe_data` is hit (`MAX_PROBE_DATA` is a
+/// reasonable default). Returns `None` if no builder ever scores at least
+/// `Score::EXTENSION` on what was available.
 pub fn probe<'a>(demuxers: &[&'static DemuxerBuilder],
-                 data: &[u8; PROBE_DATA])
+                 input: &mut ByteReader,
+                 max_probe_data: usize)
                  -> Option<&'a DemuxerBuilder> {
-    let mut max = u8::min_value();
-    let mut candidate: Option<&DemuxerBuilder> = None;
-    for builder in demuxers {
-        let score = builder.probe(data);
+    let mut len = ::std::cmp::min(PROBE_DATA, max_probe_data);
+    let mut best: Option<(Score, usize)> = None;
+
+    loop {
+        let mut round_best: Option<(Score, usize)> = None;
+        let mut need_more: Option<usize> = None;
+        let eof;
+
+        {
+            let data = match input.peek_available(len) {
+                Ok(data) => data,
+                Err(_) => break,
+            };
+            eof = data.len() < len;
+
+            for (i, builder) in demuxers.iter().enumerate() {
+                match builder.probe(data) {
+                    ProbeResult::Score(score) => {
+                        if round_best.map_or(true, |(best, _)| score > best) {
+                            round_best = Some((score, i));
+                        }
+                    }
+                    ProbeResult::NeedMoreData { at_least } => {
+                        if !eof && len < max_probe_data {
+                            need_more = Some(match need_more {
+                                Some(current) => ::std::cmp::max(current, at_least),
+                                None => at_least,
+                            });
+                        }
+                    }
+                }
+            }
+        }
 
-        if score > max {
-            max = score;
-            candidate = Some(*builder);
+        if let Some((score, i)) = round_best {
+            if best.map_or(true, |(current, _)| score > current) {
+                best = Some((score, i));
+            }
+            if score == Score::MAX {
+                break;
+            }
+        }
+
+        match need_more {
+            Some(at_least) if !eof && len < max_probe_data => {
+                // `at_least` can be 0 (an off-by-one in a format's probe is
+                // an easy mistake); always grow by at least one byte so the
+                // next round sees new data instead of spinning forever.
+                len = ::std::cmp::min(len + ::std::cmp::max(at_least, 1), max_probe_data);
+            }
+            _ => break,
         }
     }
 
-    if max > Score::EXTENSION as u8 {
-        candidate
-    } else {
-        None
+    match best {
+        Some((score, i)) if score >= Score::EXTENSION => Some(demuxers[i]),
+        _ => None,
     }
 }
 
@@ -75,13 +380,13 @@ macro_rules! module {
 
             impl Demuxer for [$name Demuxer] {
                 fn open(&mut self) $open
-                fn read_headers(&mut self) -> Result<(), Error> $read_headers
-                fn read_packet(&mut self) -> Result<Packet, Error> $read_packet
+                fn read_headers(&mut self, input: &mut ByteReader) -> Result<(), DemuxerError> $read_headers
+                fn read_packet(&mut self, input: &mut ByteReader) -> Result<Packet, DemuxerError> $read_packet
             }
 
             impl DemuxerBuilder for [$name DemuxerBuilder] {
                 fn describe(&self) -> &'static DemuxerDescription $describe
-                fn probe(&self, data: &[u8; PROBE_DATA]) -> u8 $probe
+                fn probe(&self, data: &[u8]) -> ProbeResult $probe
 
                 fn alloc(&self) -> -> Box<Demuxer> $alloc
             }
@@ -94,13 +399,14 @@ macro_rules! module {
 mod test {
     use super::*;
     use std::io::Error;
+    use std::io::Cursor;
     use data::packet::Packet;
 
     module! {
         (Test) {
             open => { () }
-            read_headers => { Ok(()) }
-            read_packet => { unimplemented!() }
+            read_headers => { let _ = input; Ok(()) }
+            read_packet => { let _ = input; unimplemented!() }
 
             describe => {
                 const D: &'static DemuxerDescription = &DemuxerDescription {
@@ -114,10 +420,10 @@ mod test {
             }
 
             probe => {
-                if data[0] == 0 {
-                    Score::MAX as u8
+                if data.get(0) == Some(&0) {
+                    ProbeResult::Score(Score::MAX)
                 } else {
-                    0
+                    ProbeResult::Score(Score::NONE)
                 }
             }
 
@@ -129,22 +435,207 @@ mod test {
         }
     }
 
+    // Needs to see the byte at offset 4999 before it can decide, so probing
+    // it exercises the growing-window loop in `probe` rather than deciding
+    // within the initial `PROBE_DATA` chunk.
+    module! {
+        (FarMatch) {
+            open => { () }
+            read_headers => { let _ = input; Ok(()) }
+            read_packet => { let _ = input; unimplemented!() }
+
+            describe => {
+                const D: &'static DemuxerDescription = &DemuxerDescription {
+                    name: "FarMatch",
+                    description: "Test demuxer that decides past the initial probe window",
+                    extensions: &["far"],
+                    mime: &["x-application/far-match-test"],
+                };
+
+                D
+            }
+
+            probe => {
+                const MARKER_OFFSET: usize = 4999;
+
+                if data.len() <= MARKER_OFFSET {
+                    ProbeResult::NeedMoreData { at_least: MARKER_OFFSET + 1 - data.len() }
+                } else if data[MARKER_OFFSET] == 0xff {
+                    ProbeResult::Score(Score::MAX)
+                } else {
+                    ProbeResult::Score(Score::NONE)
+                }
+            }
+
+            alloc => {
+                let demux = FarMatchDemuxer {};
+
+                box demux
+            }
+        }
+    }
+
+    // Always asks for more additional bytes than it was given (so the window
+    // roughly doubles each round), no matter how much data is already
+    // available, so it never reaches a verdict on its own.
+    module! {
+        (NeverDecides) {
+            open => { () }
+            read_headers => { let _ = input; Ok(()) }
+            read_packet => { let _ = input; unimplemented!() }
+
+            describe => {
+                const D: &'static DemuxerDescription = &DemuxerDescription {
+                    name: "NeverDecides",
+                    description: "Test demuxer that always asks for more data",
+                    extensions: &["never"],
+                    mime: &["x-application/never-decides-test"],
+                };
+
+                D
+            }
+
+            probe => {
+                ProbeResult::NeedMoreData { at_least: data.len() + 1 }
+            }
+
+            alloc => {
+                let demux = NeverDecidesDemuxer {};
+
+                box demux
+            }
+        }
+    }
+
     const DEMUXER_BUILDERS: [&'static DemuxerBuilder; 1] = [&TestDemuxerBuilder {}];
 
     #[test]
     fn probe_demuxer() {
-        let mut buf = [1; PROBE_DATA];
+        let mut bytes = vec![1; PROBE_DATA];
+        let mut data = Cursor::new(bytes.clone());
+        let mut reader = ByteReader::new(&mut data);
 
-        match probe(&DEMUXER_BUILDERS, &buf) {
+        match probe(&DEMUXER_BUILDERS, &mut reader, MAX_PROBE_DATA) {
             Some(_) => panic!(),
             None => (),
         };
 
-        buf[0] = 0;
+        bytes[0] = 0;
+        let mut data = Cursor::new(bytes);
+        let mut reader = ByteReader::new(&mut data);
 
-        match probe(&DEMUXER_BUILDERS, &buf) {
+        match probe(&DEMUXER_BUILDERS, &mut reader, MAX_PROBE_DATA) {
             Some(_) => (),
             None => panic!(),
         };
     }
+
+    #[test]
+    fn probe_short_stream() {
+        let mut data = Cursor::new(vec![0u8; 4]);
+        let mut reader = ByteReader::new(&mut data);
+
+        match probe(&DEMUXER_BUILDERS, &mut reader, MAX_PROBE_DATA) {
+            Some(_) => (),
+            None => panic!("a stream shorter than PROBE_DATA should still be probeable"),
+        };
+    }
+
+    #[test]
+    fn probe_grows_window_for_far_match() {
+        let mut bytes = vec![0u8; 6000];
+        bytes[4999] = 0xff;
+        let mut data = Cursor::new(bytes);
+        let mut reader = ByteReader::new(&mut data);
+
+        let builders: [&'static DemuxerBuilder; 1] = [&FarMatchDemuxerBuilder {}];
+
+        match probe(&builders, &mut reader, MAX_PROBE_DATA) {
+            Some(_) => (),
+            None => panic!("expected the far-matching demuxer to be found after growing the window"),
+        };
+    }
+
+    #[test]
+    fn probe_terminates_when_builder_never_decides() {
+        // Long enough that the window growth never runs into a clean EOF
+        // before hitting MAX_PROBE_DATA.
+        let mut data = Cursor::new(vec![0u8; MAX_PROBE_DATA * 2]);
+        let mut reader = ByteReader::new(&mut data);
+
+        let builders: [&'static DemuxerBuilder; 1] = [&NeverDecidesDemuxerBuilder {}];
+
+        match probe(&builders, &mut reader, MAX_PROBE_DATA) {
+            Some(_) => panic!("a builder that never reaches a verdict should not match"),
+            None => (),
+        };
+    }
+
+    #[test]
+    fn read_exact_or_eof_consumes_on_success() {
+        let mut data = Cursor::new(vec![1, 2, 3, 4, 5]);
+        let mut reader = ByteReader::new(&mut data);
+
+        assert_eq!(reader.read_exact_or_eof(2).unwrap(), &[1, 2]);
+        assert_eq!(reader.read_exact_or_eof(3).unwrap(), &[3, 4, 5]);
+    }
+
+    #[test]
+    fn read_exact_or_eof_leaves_cursor_on_eof() {
+        let mut data = Cursor::new(vec![1, 2, 3]);
+        let mut reader = ByteReader::new(&mut data);
+
+        assert_eq!(reader.read_exact_or_eof(2).unwrap(), &[1, 2]);
+
+        match reader.read_exact_or_eof(5) {
+            Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => (),
+            _ => panic!("expected UnexpectedEof"),
+        }
+
+        // The failed read must not have moved the cursor: the one byte
+        // left after the first read is still there to be read.
+        assert_eq!(reader.read_exact_or_eof(1).unwrap(), &[3]);
+    }
+
+    #[test]
+    fn peek_does_not_consume() {
+        let mut data = Cursor::new(vec![1, 2, 3]);
+        let mut reader = ByteReader::new(&mut data);
+
+        assert_eq!(reader.peek(2).unwrap(), &[1, 2]);
+        assert_eq!(reader.peek(2).unwrap(), &[1, 2]);
+        assert_eq!(reader.read_exact_or_eof(2).unwrap(), &[1, 2]);
+    }
+
+    #[test]
+    fn demuxer_error_maps_clean_eof() {
+        let mut data = Cursor::new(Vec::<u8>::new());
+        let mut reader = ByteReader::new(&mut data);
+
+        match reader.read_exact_or_eof(4) {
+            Err(e) => {
+                match DemuxerError::from(e) {
+                    DemuxerError::Eof => (),
+                    other => panic!("expected Eof, got {:?}", other),
+                }
+            }
+            Ok(_) => panic!(),
+        }
+    }
+
+    #[test]
+    fn demuxer_error_maps_partial_read_to_need_more_data() {
+        let mut data = Cursor::new(vec![1, 2]);
+        let mut reader = ByteReader::new(&mut data);
+
+        match reader.read_exact_or_eof(4) {
+            Err(e) => {
+                match DemuxerError::from(e) {
+                    DemuxerError::NeedMoreData => (),
+                    other => panic!("expected NeedMoreData, got {:?}", other),
+                }
+            }
+            Ok(_) => panic!(),
+        }
+    }
 }